@@ -5,6 +5,13 @@
 //! [`StateBytes`].
 
 use super::change::*;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::AlignedVec;
+use rkyv::Archive;
+use rkyv::Archived;
+use rkyv::CheckBytes;
+use rkyv::Deserialize as RkyvDeserialize;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
@@ -15,6 +22,9 @@ use std::collections::hash_map::Keys;
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 
 /// Unique ID for a step in a dataflow.
 ///
@@ -49,35 +59,376 @@ pub(crate) struct SnapshotEpoch(pub(crate) u64);
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct StoreKey(pub(crate) SnapshotEpoch, pub(crate) FlowKey);
 
+/// Which wire format the bytes in a [`StateBytes`] were written with.
+///
+/// Stored in the header of every [`StateBytes`] so a bincode- and an
+/// rkyv-encoded snapshot are never silently mixed up on read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum StateEncoding {
+    Bincode = 0,
+    Rkyv = 1,
+}
+
+impl StateEncoding {
+    fn of(tag: u8) -> Self {
+        match tag {
+            0 => Self::Bincode,
+            1 => Self::Rkyv,
+            tag => panic!("Unknown recovery state encoding tag {tag}"),
+        }
+    }
+}
+
+type MigrationFn = dyn Fn(u16, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// `(type tag, from_version) -> migration` registry, populated by
+/// [`register_migration`] and consulted by [`StateBytes::de_versioned`]
+/// / [`StateBytes::archived_versioned`].
+fn migrations() -> &'static Mutex<HashMap<(&'static str, u16), Arc<MigrationFn>>> {
+    static MIGRATIONS: OnceLock<Mutex<HashMap<(&'static str, u16), Arc<MigrationFn>>>> =
+        OnceLock::new();
+    MIGRATIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Register an upgrade path for state tagged `type_tag` from
+/// `from_version` to `from_version + 1`.
+///
+/// `migrate` is handed `from_version` (the version the payload is at
+/// when this step of the chain runs, not necessarily the version the
+/// bytes were first written with) and the header-stripped payload,
+/// and must return that payload
+/// re-encoded as `from_version + 1`. Chained upgrades (e.g. v1 -> v3)
+/// are resolved by registering each step and letting
+/// [`StateBytes::de_versioned`] walk the chain one version at a time.
+///
+/// This only takes effect for state written with [`StateBytes::ser_versioned`]
+/// / [`StateBytes::ser_rkyv_versioned`], since those are the only ones
+/// that stamp a caller-chosen, build-stable `type_tag`; plain
+/// [`StateBytes::ser`] tags itself with `std::any::type_name`, which
+/// isn't guaranteed stable across builds, so it has no durable tag to
+/// register a migration against. Each operator should register
+/// migrations for the state type(s) it owns, typically at startup,
+/// before any recovery reads happen.
+pub(crate) fn register_migration(
+    type_tag: &'static str,
+    from_version: u16,
+    migrate: impl Fn(u16, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+) {
+    migrations()
+        .lock()
+        .unwrap()
+        .insert((type_tag, from_version), Arc::new(migrate));
+}
+
+/// Parsed view of a [`StateBytes`] header: `[encoding][version: u16
+/// LE][tag_len: u8][tag bytes][payload]`.
+struct StateHeader<'a> {
+    encoding: StateEncoding,
+    version: u16,
+    type_tag: &'a str,
+    payload: &'a [u8],
+}
+
+impl<'a> StateHeader<'a> {
+    fn parse(bytes: &'a [u8]) -> Self {
+        assert!(
+            bytes.len() >= 4,
+            "Corrupt recovery state: header is truncated (got {} byte(s), need at least 4)",
+            bytes.len()
+        );
+        let encoding = StateEncoding::of(bytes[0]);
+        let version = u16::from_le_bytes([bytes[1], bytes[2]]);
+        let tag_len = bytes[3] as usize;
+        assert!(
+            bytes.len() >= 4 + tag_len,
+            "Corrupt recovery state: header claims a {tag_len}-byte type tag but only \
+            {} byte(s) follow the header prefix",
+            bytes.len() - 4
+        );
+        let type_tag = std::str::from_utf8(&bytes[4..4 + tag_len])
+            .expect("Corrupt recovery state: header type tag is not valid UTF-8");
+        let payload = &bytes[4 + tag_len..];
+        Self {
+            encoding,
+            version,
+            type_tag,
+            payload,
+        }
+    }
+
+    fn write(encoding: StateEncoding, version: u16, type_tag: &str, payload: &[u8]) -> Vec<u8> {
+        assert!(
+            type_tag.len() <= u8::MAX as usize,
+            "State type tag {type_tag:?} is too long"
+        );
+        let mut bytes = Vec::with_capacity(4 + type_tag.len() + payload.len());
+        bytes.push(encoding as u8);
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.push(type_tag.len() as u8);
+        bytes.extend_from_slice(type_tag.as_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+}
+
+/// Either `header.payload` untouched, or the result of walking it
+/// through the migration chain. Kept as a borrow in the common case
+/// so callers that only need a `&[u8]` (e.g. copying straight into an
+/// `AlignedVec` for [`StateBytes::archived`]) don't pay for an extra
+/// allocation when no migration ran.
+enum MigratedPayload<'a> {
+    Unchanged(&'a [u8]),
+    Migrated(Vec<u8>),
+}
+
+impl<'a> MigratedPayload<'a> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Unchanged(bytes) => bytes,
+            Self::Migrated(bytes) => bytes,
+        }
+    }
+}
+
+/// Walk the migration chain, if any, to bring `header`'s payload up
+/// to `target_version`.
+fn migrate_to_current<'a>(
+    t_name: &str,
+    type_tag: &str,
+    target_version: u16,
+    header: &StateHeader<'a>,
+) -> MigratedPayload<'a> {
+    assert_eq!(
+        header.type_tag,
+        type_tag,
+        "Recovery state tagged {:?} does not match expected type {t_name} (tag {:?}); \
+        did you rename a step and forget to migrate its state?",
+        header.type_tag,
+        type_tag,
+    );
+    if header.version == target_version {
+        return MigratedPayload::Unchanged(header.payload);
+    }
+    let mut version = header.version;
+    let mut payload = header.payload.to_vec();
+    while version != target_version {
+        let migrate = migrations()
+            .lock()
+            .unwrap()
+            .get(&(type_tag, version))
+            .cloned()
+            .unwrap_or_else(|| {
+                panic!(
+                    "No migration registered for recovery state type {t_name} \
+                    (tag {type_tag:?}) from schema version {version} to {target_version}; \
+                    did you bump the schema version without registering a migration?"
+                )
+            });
+        payload = migrate(version, &payload);
+        version += 1;
+    }
+    MigratedPayload::Migrated(payload)
+}
+
 /// A snapshot of state for a specific key within a step.
 ///
 /// The recovery system only deals in bytes so each operator can store
 /// custom types without going through Rust generic gymnastics.
+///
+/// Prefixed with a self-describing [`StateHeader`] so a stale
+/// snapshot can be migrated forward on read instead of silently
+/// corrupting state or panicking on a generic mismatch.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct StateBytes(pub(crate) Vec<u8>);
 
 impl StateBytes {
     /// Serialize this state object from an operator into bytes the
     /// recovery system can store.
+    ///
+    /// Tags the bytes with `std::any::type_name::<T>()` and schema
+    /// version `0`. That's convenient for existing call sites (no
+    /// extra trait to implement), but `type_name` isn't guaranteed
+    /// stable across builds, so state written this way can't reliably
+    /// be migrated across a binary upgrade. Operators that care about
+    /// that should use [`Self::ser_versioned`] with a hand-picked,
+    /// stable tag instead.
     pub(crate) fn ser<T: Serialize>(obj: &T) -> Self {
-        // TODO: Figure out if there's a more robust-to-evolution way
-        // to serialize this key. If the serialization changes between
-        // versions, then recovery doesn't work. Or if we use an
-        // encoding that isn't deterministic.
+        Self::ser_versioned(obj, type_name::<T>(), 0)
+    }
+
+    /// Like [`Self::ser`], but with an explicit, caller-chosen stable
+    /// `type_tag` and `schema_version` instead of the `type_name`/`0`
+    /// default. Pair with [`Self::de_versioned`] and
+    /// [`register_migration`] to make a state type's recovery data
+    /// migratable across rolling upgrades.
+    pub(crate) fn ser_versioned<T: Serialize>(obj: &T, type_tag: &str, schema_version: u16) -> Self {
         let t_name = type_name::<T>();
-        Self(
-            bincode::serialize(obj)
-                .unwrap_or_else(|_| panic!("Error serializing recovery state type {t_name})")),
-        )
+        let payload = bincode::serialize(obj)
+            .unwrap_or_else(|_| panic!("Error serializing recovery state type {t_name})"));
+        Self(StateHeader::write(
+            StateEncoding::Bincode,
+            schema_version,
+            type_tag,
+            &payload,
+        ))
     }
 
     /// Deserialize these bytes from the recovery system into a state
     /// object that an operator can use.
+    ///
+    /// See [`Self::ser`] for the caveats of the default
+    /// `type_name`/`0` tag this assumes the bytes were written with.
     pub(crate) fn de<T: DeserializeOwned>(self) -> T {
+        self.de_versioned(type_name::<T>(), 0)
+    }
+
+    /// Like [`Self::de`], but against an explicit, caller-chosen
+    /// `type_tag`/`schema_version` rather than the `type_name`/`0`
+    /// default — use the same ones passed to the
+    /// [`Self::ser_versioned`] call that produced these bytes.
+    ///
+    /// If the bytes were written by an older schema version, routes
+    /// them through any migrations registered for `type_tag` via
+    /// [`register_migration`] before decoding.
+    pub(crate) fn de_versioned<T: DeserializeOwned>(self, type_tag: &str, schema_version: u16) -> T {
         let t_name = type_name::<T>();
-        bincode::deserialize(&self.0)
+        let header = StateHeader::parse(&self.0);
+        assert_eq!(
+            header.encoding,
+            StateEncoding::Bincode,
+            "Recovery state type {t_name} was not encoded with bincode; \
+            is bincode- and rkyv-encoded state mixed in the same store?"
+        );
+        let payload = migrate_to_current(t_name, type_tag, schema_version, &header);
+        bincode::deserialize(payload.as_slice())
             .unwrap_or_else(|_| panic!("Error deserializing recovery state type {t_name})"))
     }
+
+    /// Serialize this state object from an operator into bytes using
+    /// rkyv, so it can later be read back with [`Self::archived`]
+    /// without a full deserialization pass.
+    ///
+    /// See [`Self::ser`] for the caveats of the default
+    /// `type_name`/`0` tag; use [`Self::ser_rkyv_versioned`] for a
+    /// migratable, build-stable tag instead.
+    pub(crate) fn ser_rkyv<T>(obj: &T) -> Self
+    where
+        T: rkyv::Serialize<AllocSerializer<256>>,
+    {
+        Self::ser_rkyv_versioned(obj, type_name::<T>(), 0)
+    }
+
+    /// Like [`Self::ser_rkyv`], but with an explicit, caller-chosen
+    /// stable `type_tag` and `schema_version`.
+    pub(crate) fn ser_rkyv_versioned<T>(obj: &T, type_tag: &str, schema_version: u16) -> Self
+    where
+        T: rkyv::Serialize<AllocSerializer<256>>,
+    {
+        let t_name = type_name::<T>();
+        let payload = rkyv::to_bytes::<_, 256>(obj)
+            .unwrap_or_else(|_| panic!("Error serializing recovery state type {t_name} with rkyv"));
+        Self(StateHeader::write(
+            StateEncoding::Rkyv,
+            schema_version,
+            type_tag,
+            &payload,
+        ))
+    }
+
+    /// Get a zero-copy archived view of these bytes without running a
+    /// full deserialization pass.
+    ///
+    /// See [`Self::ser`] for the caveats of the default
+    /// `type_name`/`0` tag this assumes the bytes were written with;
+    /// use [`Self::archived_versioned`] together with
+    /// [`Self::ser_rkyv_versioned`] for migratable state.
+    pub(crate) fn archived<T>(&self) -> ArchivedStateBytes<T>
+    where
+        T: Archive,
+        Archived<T>: for<'a> CheckBytes<DefaultValidator<'a>>,
+    {
+        self.archived_versioned(type_name::<T>(), 0)
+    }
+
+    /// Like [`Self::archived`], but against an explicit, caller-chosen
+    /// `type_tag`/`schema_version` rather than the `type_name`/`0`
+    /// default — use the same ones passed to the
+    /// [`Self::ser_rkyv_versioned`] call that produced these bytes.
+    ///
+    /// Recovery bytes come from disk/an external store, so this goes
+    /// through rkyv's validating reader (`check_archived_root`): a
+    /// corrupt or truncated buffer is bubbled up as a panic rather
+    /// than producing a dangling or mis-aligned reference. As with
+    /// [`Self::de_versioned`], bytes written by an older schema
+    /// version are routed through a registered migration first.
+    ///
+    /// The archive is copied into a freshly allocated [`AlignedVec`]
+    /// rather than read out of `self.0` at a header offset, since
+    /// `check_archived_root` requires the buffer it's handed to start
+    /// at the alignment rkyv baked into the archive, which a `Vec<u8>`
+    /// sliced past the header doesn't uphold.
+    pub(crate) fn archived_versioned<T>(
+        &self,
+        type_tag: &str,
+        schema_version: u16,
+    ) -> ArchivedStateBytes<T>
+    where
+        T: Archive,
+        Archived<T>: for<'a> CheckBytes<DefaultValidator<'a>>,
+    {
+        let t_name = type_name::<T>();
+        let header = StateHeader::parse(&self.0);
+        assert_eq!(
+            header.encoding,
+            StateEncoding::Rkyv,
+            "Recovery state type {t_name} was not encoded with rkyv; \
+            is bincode- and rkyv-encoded state mixed in the same store?"
+        );
+        let payload = migrate_to_current(t_name, type_tag, schema_version, &header);
+        let mut buf = AlignedVec::with_capacity(payload.as_slice().len());
+        buf.extend_from_slice(payload.as_slice());
+        rkyv::check_archived_root::<T>(&buf)
+            .unwrap_or_else(|err| panic!("Error validating archived recovery state type {t_name}: {err}"));
+        ArchivedStateBytes {
+            buf,
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+/// A validated, zero-copy view into an rkyv-encoded [`StateBytes`].
+///
+/// Obtained via [`StateBytes::archived`]. Holds the archive in an
+/// aligned buffer and hands out `&Archived<T>` references into it on
+/// demand, rather than a single reference with an awkward lifetime
+/// tied back to the original bytes.
+#[derive(Debug)]
+pub(crate) struct ArchivedStateBytes<T> {
+    buf: AlignedVec,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> ArchivedStateBytes<T>
+where
+    T: Archive,
+{
+    /// The validation already happened in [`StateBytes::archived`], so
+    /// this is a zero-copy cast, not another deserialization pass.
+    pub(crate) fn get(&self) -> &Archived<T> {
+        unsafe { rkyv::archived_root::<T>(&self.buf) }
+    }
+
+    /// Deserialize into an owned `T`, for operators that need to hold
+    /// onto the state past the lifetime of this archived view.
+    pub(crate) fn to_owned(&self) -> T
+    where
+        Archived<T>: RkyvDeserialize<T, rkyv::Infallible>,
+    {
+        self.get()
+            .deserialize(&mut rkyv::Infallible)
+            .unwrap_or_else(|_| unreachable!("rkyv::Infallible deserializer never fails"))
+    }
 }
 
 /// A change to state within the dataflow.
@@ -158,8 +509,8 @@ impl FlowStateBytes {
             if !self.0.is_empty() {
                 tracing::warn!(
                     "No resume state for {step_id:?}, \
-                    but other steps have state; did you add or rename a \
-                    step and forget to init or migrate state data?"
+                    but other steps have state; did you add or rename this \
+                    step?"
                 );
             }
             Default::default()
@@ -184,3 +535,52 @@ impl KWriter<FlowKey, StateBytes> for FlowStateBytes {
             .write(KChange(state_key, change));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+    #[archive(check_bytes)]
+    struct Counter {
+        count: u64,
+    }
+
+    #[test]
+    fn archived_is_a_zero_copy_view_of_the_rkyv_payload() {
+        let value = Counter { count: 7 };
+        let bytes = StateBytes::ser_rkyv(&value);
+        let archived = bytes.archived::<Counter>();
+        assert_eq!(archived.get().count, 7);
+        assert_eq!(archived.to_owned(), value);
+    }
+
+    #[test]
+    #[should_panic(expected = "was not encoded with rkyv")]
+    fn archived_rejects_bincode_encoded_bytes() {
+        StateBytes::ser(&42u64).archived::<u64>();
+    }
+
+    #[test]
+    fn state_header_round_trips_through_write_and_parse() {
+        let bytes = StateHeader::write(StateEncoding::Bincode, 3, "my_step::MyState", b"payload");
+        let header = StateHeader::parse(&bytes);
+        assert_eq!(header.encoding, StateEncoding::Bincode);
+        assert_eq!(header.version, 3);
+        assert_eq!(header.type_tag, "my_step::MyState");
+        assert_eq!(header.payload, b"payload");
+    }
+
+    #[test]
+    #[should_panic(expected = "header is truncated")]
+    fn state_header_parse_rejects_bytes_shorter_than_the_fixed_prefix() {
+        StateHeader::parse(&[0, 1, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "only 0 byte(s) follow the header prefix")]
+    fn state_header_parse_rejects_a_type_tag_longer_than_the_remaining_bytes() {
+        // Claims a 5-byte type tag but no bytes for it (or a payload) follow.
+        StateHeader::parse(&[0, 1, 0, 5]);
+    }
+}
@@ -0,0 +1,861 @@
+//! Raft-replicated state store backend.
+//!
+//! Wraps [`openraft`] so [`StoreChange`]s are replicated across
+//! workers as entries in a Raft log, rather than only ever written to
+//! a single local store. Each committed entry is applied into an
+//! in-memory keyed state map ([`RecoveryStateMachine`]); periodic
+//! [`FlowStateBytes`](super::model::state::FlowStateBytes) snapshots
+//! of that map become Raft snapshots via [`RecoverySnapshotBuilder`],
+//! and installing a snapshot is also what restores
+//! [`RaftStateStore::read`]'s resume data on startup.
+//!
+//! [`SnapshotEpoch`] is a natural fit for the Raft snapshot/compaction
+//! boundary: once a snapshot at epoch N is installed, log entries
+//! keyed with epoch N or older are superseded and
+//! [`RaftLogStore::truncate_before`] drops them, the same way
+//! [`StoreChangeSummary`] already lets the GC component drop full
+//! state without the payload.
+//!
+//! The log, the vote, and the purge watermark are mirrored to disk by
+//! [`DurableLog`] before any write is acknowledged, so a crashed and
+//! restarted node comes back with the same log and the same vote it
+//! had before — Raft's safety argument depends on the vote never
+//! being re-cast for a term the node already voted in, which an
+//! in-memory-only store can't guarantee across a restart.
+
+use super::model::change::*;
+use super::model::state::{SnapshotEpoch, StateBytes, StateReader, StateWriter, StoreKey};
+use openraft::storage::LogFlushed;
+use openraft::storage::LogState;
+use openraft::storage::Snapshot;
+use openraft::storage::SnapshotMeta;
+use openraft::BasicNode;
+use openraft::Entry;
+use openraft::EntryPayload;
+use openraft::LogId;
+use openraft::OptionalSend;
+use openraft::RaftLogReader;
+use openraft::RaftLogStorage;
+use openraft::RaftSnapshotBuilder;
+use openraft::RaftStateMachine;
+use openraft::StorageError;
+use openraft::StorageIOError;
+use openraft::StoredMembership;
+use openraft::Vote;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Node ID type for this Raft group. Bytewax workers are plain
+/// integer-indexed, so we reuse that rather than inventing UUIDs.
+pub(crate) type NodeId = u64;
+
+openraft::declare_raft_types!(
+    /// Raft type config for the recovery state store: log entries are
+    /// [`StoreChange`]s, reads never need a response payload, and
+    /// snapshots are plain byte buffers (see [`RecoverySnapshotBuilder`]).
+    pub(crate) TypeConfig:
+        D = StoreChange,
+        R = (),
+        NodeId = NodeId,
+        Node = BasicNode,
+        SnapshotData = Cursor<Vec<u8>>,
+);
+
+/// The last-applied position of the state machine, expressed in both
+/// Raft's log-id terms and in the recovery system's own
+/// [`SnapshotEpoch`] terms.
+///
+/// Keeping both lets a `RaftSnapshotBuilder` snapshot line up with
+/// Raft's log compaction *and* be used directly as a resume point by
+/// the rest of the recovery system. [`RaftLogStore::install_snapshot`]
+/// raises `epoch` to the highest epoch among the restored keys, and
+/// truncates the log up to that restored-key epoch, not up to this
+/// field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct AppliedPosition {
+    pub(crate) log_id: Option<LogId<NodeId>>,
+    pub(crate) epoch: Option<SnapshotEpoch>,
+}
+
+/// In-memory keyed state, rebuilt by applying committed log entries
+/// and by installing snapshots.
+///
+/// This is intentionally the *only* place `StoreKey -> StateBytes`
+/// state lives; there's no separate local store underneath like the
+/// single-process backends have, since consensus is what's making
+/// this durable.
+#[derive(Debug, Default)]
+pub(crate) struct RecoveryStateMachine {
+    store: HashMap<StoreKey, StateBytes>,
+    applied: AppliedPosition,
+    last_membership: StoredMembership<NodeId, BasicNode>,
+}
+
+impl RecoveryStateMachine {
+    /// Apply a single committed [`StoreChange`] into the keyed state
+    /// map. By the time an entry reaches here, Raft has already
+    /// guaranteed it's committed on a majority of the group.
+    fn apply_change(&mut self, KChange(key, change): StoreChange) {
+        let epoch = key.0;
+        match change {
+            Change::Upsert(bytes) => {
+                self.store.insert(key, bytes);
+            }
+            Change::Discard => {
+                self.store.remove(&key);
+            }
+        }
+        self.applied.epoch = self.applied.epoch.max(Some(epoch));
+    }
+}
+
+/// Builds Raft snapshots out of a [`RecoveryStateMachine`].
+///
+/// Streams the `StoreKey -> StateBytes` map into the snapshot buffer
+/// entry-by-entry rather than collecting it into one big in-memory
+/// blob first, so a snapshot's peak memory use is bounded the same
+/// way GC already bounds `StoreChangeSummary` size: by how much state
+/// is live at the snapshot epoch, not by how much history ever
+/// existed.
+#[derive(Clone)]
+pub(crate) struct RecoverySnapshotBuilder {
+    state_machine: Arc<RwLock<RecoveryStateMachine>>,
+}
+
+impl RaftSnapshotBuilder<TypeConfig> for RecoverySnapshotBuilder {
+    async fn build_snapshot(&mut self) -> Result<Snapshot<TypeConfig>, StorageError<NodeId>> {
+        let state_machine = self.state_machine.read().await;
+
+        // Stream `(StoreKey, StateBytes)` pairs out with bincode's
+        // `serialize_into`, which writes directly to the `Vec<u8>`
+        // buffer rather than building each entry's bytes separately
+        // first and then copying them in.
+        let mut data = Vec::new();
+        for (key, bytes) in state_machine.store.iter() {
+            bincode::serialize_into(&mut data, &(key, bytes))
+                .map_err(|err| StorageIOError::write_snapshot(None, &err))?;
+        }
+
+        let snapshot_id = state_machine
+            .applied
+            .log_id
+            .map(|log_id| format!("{}-{}", log_id.leader_id, log_id.index))
+            .unwrap_or_else(|| "0-0".into());
+
+        let meta = SnapshotMeta {
+            // Only Raft's included log id goes into the metadata; the
+            // last-applied `SnapshotEpoch` is not stored in it. On
+            // install, `RaftLogStore::install_snapshot` takes the
+            // highest epoch among the snapshot's keys as the point up
+            // to which log entries are truncated.
+            last_log_id: state_machine.applied.log_id,
+            last_membership: state_machine.last_membership.clone(),
+            snapshot_id,
+        };
+
+        drop(state_machine);
+
+        Ok(Snapshot {
+            meta,
+            snapshot: Box::new(Cursor::new(data)),
+        })
+    }
+}
+
+/// Disk-backed persistence for the Raft log, the vote, and the purge
+/// watermark.
+///
+/// Everything here is plain [`std::fs`]: a length-prefixed, `fsync`'d
+/// append-only file for the log, and atomic (write-to-a-temp-file,
+/// `fsync`, `rename`) whole-file writes for the vote and the purge
+/// watermark, since those are small and only ever replaced wholesale.
+/// An append-only file can't drop an interior or trailing range of
+/// records in place, so [`Self::rewrite_log`] is used instead for
+/// [`RaftLogStore::truncate`]/[`RaftLogStore::purge`], which are rare
+/// compared to [`Self::append_entries`].
+///
+/// This intentionally doesn't reach for an external embedded-database
+/// crate (e.g. sled/redb): the recovery system's whole premise is no
+/// external dependency beyond the filesystem, and the access pattern
+/// here (append, occasionally compact, replay once at startup) is
+/// simple enough not to need one.
+struct DurableLog {
+    dir: PathBuf,
+}
+
+impl DurableLog {
+    fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.join("log")
+    }
+
+    fn vote_path(&self) -> PathBuf {
+        self.dir.join("vote")
+    }
+
+    fn last_purged_path(&self) -> PathBuf {
+        self.dir.join("last_purged")
+    }
+
+    /// Replay the on-disk log, vote, and purge watermark into memory.
+    /// Called once, at construction; every mutation afterwards is
+    /// mirrored to disk before the in-memory copy is updated, so this
+    /// is the only place bytes are read back off disk.
+    fn load(
+        &self,
+    ) -> std::io::Result<(
+        BTreeMap<u64, Entry<TypeConfig>>,
+        Option<Vote<NodeId>>,
+        Option<LogId<NodeId>>,
+    )> {
+        let mut log = BTreeMap::new();
+        match std::fs::File::open(self.log_path()) {
+            Ok(file) => {
+                let mut reader = std::io::BufReader::new(file);
+                while let Some(bytes) = read_record(&mut reader)? {
+                    let entry: Entry<TypeConfig> = bincode::deserialize(&bytes)
+                        .unwrap_or_else(|err| panic!("Corrupt recovery log record: {err}"));
+                    log.insert(entry.log_id.index, entry);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let vote = read_whole_file(&self.vote_path())?.map(|bytes| {
+            bincode::deserialize(&bytes)
+                .unwrap_or_else(|err| panic!("Corrupt recovery vote file: {err}"))
+        });
+        let last_purged = read_whole_file(&self.last_purged_path())?.map(|bytes| {
+            bincode::deserialize(&bytes)
+                .unwrap_or_else(|err| panic!("Corrupt recovery purge-watermark file: {err}"))
+        });
+        Ok((log, vote, last_purged))
+    }
+
+    /// Append `entries` to the log file and `fsync` before returning,
+    /// so a crash right after this call can't lose an entry the
+    /// caller was told was durable.
+    fn append_entries(&self, entries: &[Entry<TypeConfig>]) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path())?;
+        // If a record write fails partway through (e.g. ENOSPC on the
+        // 3rd of 5 entries), truncate back to the length the file had
+        // before this call started rather than leaving a partially
+        // written record at the tail: anything else would desync
+        // future appends' record boundaries and corrupt replay.
+        let good_len = file.metadata()?.len();
+        for entry in entries {
+            let bytes = bincode::serialize(entry)
+                .unwrap_or_else(|err| panic!("Error serializing recovery log entry: {err}"));
+            if let Err(err) = write_record(&mut file, &bytes) {
+                file.set_len(good_len)?;
+                return Err(err);
+            }
+        }
+        file.sync_all()
+    }
+
+    /// Replace the on-disk log with exactly `entries`. Used by
+    /// [`RaftLogStore::truncate`]/[`RaftLogStore::purge`], where a
+    /// range of records needs to disappear rather than just grow.
+    fn rewrite_log(&self, entries: &BTreeMap<u64, Entry<TypeConfig>>) -> std::io::Result<()> {
+        let tmp_path = self.dir.join("log.tmp");
+        {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            for entry in entries.values() {
+                let bytes = bincode::serialize(entry)
+                    .unwrap_or_else(|err| panic!("Error serializing recovery log entry: {err}"));
+                write_record(&mut file, &bytes)?;
+            }
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, self.log_path())?;
+        sync_dir(&self.dir)
+    }
+
+    fn save_vote(&self, vote: &Vote<NodeId>) -> std::io::Result<()> {
+        let bytes = bincode::serialize(vote)
+            .unwrap_or_else(|err| panic!("Error serializing recovery vote: {err}"));
+        write_atomic(&self.dir, &self.vote_path(), &bytes)
+    }
+
+    fn save_last_purged(&self, log_id: &LogId<NodeId>) -> std::io::Result<()> {
+        let bytes = bincode::serialize(log_id)
+            .unwrap_or_else(|err| panic!("Error serializing recovery purge watermark: {err}"));
+        write_atomic(&self.dir, &self.last_purged_path(), &bytes)
+    }
+}
+
+/// Write `bytes` to `path` without ever leaving behind a partially
+/// written file: write to a sibling temp file, `fsync` its contents,
+/// then `rename` over `path` (atomic on the same filesystem) and
+/// `fsync` the containing directory so the rename itself survives a
+/// crash, not just the bytes it points at.
+fn write_atomic(dir: &Path, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    sync_dir(dir)
+}
+
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// One `[len: u64 LE][bytes]` record.
+fn write_record(file: &mut std::fs::File, bytes: &[u8]) -> std::io::Result<()> {
+    file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    file.write_all(bytes)
+}
+
+/// Read one `[len: u64 LE][bytes]` record, or `None` at EOF between
+/// records, including an EOF that cuts a length prefix short. An EOF
+/// inside a record's bytes is an error.
+fn read_record(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 8];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u64::from_le_bytes(len_bytes) as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+fn read_whole_file(path: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Raft-replicated implementation of [`StateWriter`]/[`StateReader`].
+///
+/// Writes submit a [`StoreChange`] to the Raft group as a regular
+/// client write and only return once it's committed; reads come from
+/// the local copy of the state machine, which on startup is restored
+/// by [`RaftLogStore::install_snapshot`] installing the last snapshot
+/// and then replaying whatever log tail Raft applies after it — the
+/// same "snapshot plus tail" resume shape the other state stores use,
+/// just backed by Raft instead of a single SQLite/Kafka store.
+pub(crate) struct RaftStateStore {
+    raft: openraft::Raft<TypeConfig>,
+    state_machine: Arc<RwLock<RecoveryStateMachine>>,
+    // Drained by `read()`, one entry at a time, rather than re-reading
+    // the whole state machine on every call: it's lazily filled from
+    // the local state machine's contents the first time a resume read
+    // happens, then popped down to empty.
+    resume_queue: Option<VecDeque<StoreChange>>,
+}
+
+impl RaftStateStore {
+    pub(crate) fn new(
+        raft: openraft::Raft<TypeConfig>,
+        state_machine: Arc<RwLock<RecoveryStateMachine>>,
+    ) -> Self {
+        Self {
+            raft,
+            state_machine,
+            resume_queue: None,
+        }
+    }
+
+    pub(crate) fn snapshot_builder(&self) -> RecoverySnapshotBuilder {
+        RecoverySnapshotBuilder {
+            state_machine: self.state_machine.clone(),
+        }
+    }
+
+    /// Block until this node's locally applied log id has caught up
+    /// to the cluster's committed log id.
+    ///
+    /// Without this, the very first resume read after a restart can
+    /// race the replay of the log tail after the last installed
+    /// snapshot: `self.state_machine` would already reflect the
+    /// snapshot but not yet the committed entries after it, and
+    /// [`Self::resume_reads`] would hand back a silently incomplete
+    /// (or, on an otherwise-empty store, silently empty) view that
+    /// looks indistinguishable from "no prior state".
+    async fn wait_for_caught_up(&self) {
+        let mut metrics_rx = self.raft.metrics();
+        loop {
+            let metrics = metrics_rx.borrow().clone();
+            let caught_up = match (metrics.last_applied, metrics.committed) {
+                (_, None) => true,
+                (Some(last_applied), Some(committed)) => last_applied >= committed,
+                (None, Some(_)) => false,
+            };
+            if caught_up {
+                return;
+            }
+            if metrics_rx.changed().await.is_err() {
+                // The Raft instance is shutting down; there's nothing
+                // left to catch up to.
+                return;
+            }
+        }
+    }
+
+    /// Resume read: replay whatever of the local state machine has
+    /// already been rebuilt (by installing the last snapshot, then
+    /// applying the log tail after it) into `StoreKey -> StateBytes`
+    /// pairs.
+    ///
+    /// Waits for the local node to catch up to the cluster's
+    /// committed index first; see [`Self::wait_for_caught_up`].
+    async fn resume_reads(&self) -> VecDeque<StoreChange> {
+        self.wait_for_caught_up().await;
+        self.state_machine
+            .read()
+            .await
+            .store
+            .iter()
+            .map(|(key, bytes)| KChange(key.clone(), Change::Upsert(bytes.clone())))
+            .collect()
+    }
+}
+
+impl KWriter<StoreKey, Change<StateBytes>> for RaftStateStore {
+    fn write(&mut self, kchange: StoreChange) {
+        // Writes to the replicated store are a consensus round trip,
+        // so this blocks the calling thread until the write commits.
+        // `block_in_place` hands this thread's other work off to
+        // another worker thread for the duration instead of just
+        // blocking it in place, which on a multi-threaded runtime
+        // would otherwise risk starving whichever worker is needed to
+        // drive `self.raft`'s own internal tasks to completion,
+        // deadlocking the write against itself.
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(self.raft.client_write(kchange))
+                .unwrap_or_else(|err| panic!("Error replicating recovery state change: {err}"));
+        });
+    }
+}
+
+impl KReader<StoreKey, Change<StateBytes>> for RaftStateStore {
+    fn read(&mut self) -> Option<StoreChange> {
+        let queue = match self.resume_queue.as_mut() {
+            Some(queue) => queue,
+            None => {
+                let queue = tokio::task::block_in_place(|| {
+                    tokio::runtime::Handle::current().block_on(self.resume_reads())
+                });
+                self.resume_queue.insert(queue)
+            }
+        };
+        queue.pop_front()
+    }
+}
+
+impl StateWriter for RaftStateStore {}
+impl StateReader for RaftStateStore {}
+
+/// Log-storage side of the Raft backend.
+///
+/// Implements both halves of `openraft`'s storage split: [`RaftLogStorage`]
+/// (and its [`RaftLogReader`] supertrait) persists the replicated log
+/// and the vote, while [`RaftStateMachine`] applies committed entries
+/// into, and restores snapshots into, the shared [`RecoveryStateMachine`].
+///
+/// The log and the vote are mirrored to disk through [`DurableLog`]
+/// before any of `append`/`save_vote`/`truncate`/`purge` return, so a
+/// restarted node's in-memory copies (`log`, `vote`, `last_purged`)
+/// always agree with what was last durably acknowledged.
+pub(crate) struct RaftLogStore {
+    state_machine: Arc<RwLock<RecoveryStateMachine>>,
+    durable: Arc<DurableLog>,
+    log: BTreeMap<u64, Entry<TypeConfig>>,
+    vote: Option<Vote<NodeId>>,
+    last_purged: Option<LogId<NodeId>>,
+}
+
+impl Clone for RaftLogStore {
+    fn clone(&self) -> Self {
+        Self {
+            state_machine: self.state_machine.clone(),
+            durable: self.durable.clone(),
+            log: self.log.clone(),
+            vote: self.vote,
+            last_purged: self.last_purged,
+        }
+    }
+}
+
+impl RaftLogStore {
+    /// Open (creating if needed) the on-disk log under `base_dir` and
+    /// replay it into memory.
+    pub(crate) fn new(state_machine: Arc<RwLock<RecoveryStateMachine>>, base_dir: PathBuf) -> Self {
+        let durable = DurableLog::open(base_dir)
+            .unwrap_or_else(|err| panic!("Error opening recovery log directory: {err}"));
+        let (log, vote, last_purged) = durable
+            .load()
+            .unwrap_or_else(|err| panic!("Error replaying recovery log from disk: {err}"));
+        Self {
+            state_machine,
+            durable: Arc::new(durable),
+            log,
+            vote,
+            last_purged,
+        }
+    }
+
+    /// Apply already-committed entries into the keyed state map: each
+    /// `Normal` entry's `StoreChange` becomes a `KChange(StoreKey,
+    /// Change<StateBytes>)` write, and a `Membership` entry updates
+    /// the state machine's recorded cluster membership.
+    async fn apply_entries(
+        &mut self,
+        entries: &[Entry<TypeConfig>],
+    ) -> Result<(), StorageError<NodeId>> {
+        let mut state_machine = self.state_machine.write().await;
+        for entry in entries {
+            state_machine.applied.log_id = Some(entry.log_id);
+            match &entry.payload {
+                EntryPayload::Normal(change) => state_machine.apply_change(change.clone()),
+                EntryPayload::Membership(membership) => {
+                    state_machine.last_membership =
+                        StoredMembership::new(Some(entry.log_id), membership.clone());
+                }
+                EntryPayload::Blank => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Once a snapshot at epoch N is installed, log entries whose
+    /// `StoreKey`'s `SnapshotEpoch` is <= N are superseded and can be
+    /// dropped from `self.log`. Mirrors the truncated log to disk
+    /// before returning, same as `truncate`/`purge`.
+    fn truncate_before(&mut self, epoch: SnapshotEpoch) -> Result<(), StorageError<NodeId>> {
+        self.log.retain(|_, entry| {
+            !matches!(
+                &entry.payload,
+                EntryPayload::Normal(KChange(StoreKey(log_epoch, _), _)) if *log_epoch <= epoch
+            )
+        });
+        self.durable
+            .rewrite_log(&self.log)
+            .map_err(|err| StorageIOError::write_logs(&err))?;
+        Ok(())
+    }
+}
+
+impl RaftLogReader<TypeConfig> for RaftLogStore {
+    async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + OptionalSend>(
+        &mut self,
+        range: RB,
+    ) -> Result<Vec<Entry<TypeConfig>>, StorageError<NodeId>> {
+        Ok(self
+            .log
+            .range(range)
+            .map(|(_, entry)| entry.clone())
+            .collect())
+    }
+
+    async fn read_vote(&mut self) -> Result<Option<Vote<NodeId>>, StorageError<NodeId>> {
+        Ok(self.vote)
+    }
+}
+
+impl RaftLogStorage<TypeConfig> for RaftLogStore {
+    type LogReader = Self;
+
+    async fn get_log_state(&mut self) -> Result<LogState<TypeConfig>, StorageError<NodeId>> {
+        let last_log_id = self.log.values().last().map(|entry| entry.log_id);
+        Ok(LogState {
+            last_purged_log_id: self.last_purged,
+            last_log_id: last_log_id.or(self.last_purged),
+        })
+    }
+
+    async fn save_vote(&mut self, vote: &Vote<NodeId>) -> Result<(), StorageError<NodeId>> {
+        self.durable
+            .save_vote(vote)
+            .map_err(|err| StorageIOError::write_vote(&err))?;
+        self.vote = Some(*vote);
+        Ok(())
+    }
+
+    async fn append<I>(
+        &mut self,
+        entries: I,
+        callback: LogFlushed<TypeConfig>,
+    ) -> Result<(), StorageError<NodeId>>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>> + OptionalSend,
+    {
+        let entries: Vec<_> = entries.into_iter().collect();
+        if let Err(err) = self.durable.append_entries(&entries) {
+            callback.log_io_completed(Err(StorageIOError::write_logs(&err).into()));
+            return Err(StorageIOError::write_logs(&err).into());
+        }
+        for entry in entries {
+            self.log.insert(entry.log_id.index, entry);
+        }
+        callback.log_io_completed(Ok(()));
+        Ok(())
+    }
+
+    async fn truncate(&mut self, log_id: LogId<NodeId>) -> Result<(), StorageError<NodeId>> {
+        self.log.split_off(&log_id.index);
+        self.durable
+            .rewrite_log(&self.log)
+            .map_err(|err| StorageIOError::write_logs(&err))?;
+        Ok(())
+    }
+
+    async fn purge(&mut self, log_id: LogId<NodeId>) -> Result<(), StorageError<NodeId>> {
+        self.log = self.log.split_off(&(log_id.index + 1));
+        self.last_purged = Some(self.last_purged.map_or(log_id, |prev| prev.max(log_id)));
+        // Persist the new watermark *before* rewriting the log file
+        // with the purged entries dropped: if we crash between the
+        // two steps, on-disk `last_purged` must never claim a higher
+        // watermark than the entries the log file still has, or a
+        // replayed `get_log_state` would believe a gap of entries
+        // exists that were never actually purged. Persisting the
+        // watermark first means a crash here just leaves some
+        // already-purged entries physically on disk a little longer,
+        // which is harmless — they're purged again next time.
+        self.durable
+            .save_last_purged(&self.last_purged.expect("just set above"))
+            .map_err(|err| StorageIOError::write_logs(&err))?;
+        self.durable
+            .rewrite_log(&self.log)
+            .map_err(|err| StorageIOError::write_logs(&err))?;
+        Ok(())
+    }
+
+    async fn get_log_reader(&mut self) -> Self::LogReader {
+        self.clone()
+    }
+}
+
+impl RaftStateMachine<TypeConfig> for RaftLogStore {
+    type SnapshotBuilder = RecoverySnapshotBuilder;
+
+    async fn applied_state(
+        &mut self,
+    ) -> Result<(Option<LogId<NodeId>>, StoredMembership<NodeId, BasicNode>), StorageError<NodeId>>
+    {
+        let state_machine = self.state_machine.read().await;
+        Ok((state_machine.applied.log_id, state_machine.last_membership.clone()))
+    }
+
+    async fn apply<I>(&mut self, entries: I) -> Result<Vec<()>, StorageError<NodeId>>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>> + OptionalSend,
+    {
+        let entries: Vec<_> = entries.into_iter().collect();
+        let responses = vec![(); entries.len()];
+        self.apply_entries(&entries).await?;
+        Ok(responses)
+    }
+
+    fn get_snapshot_builder(&mut self) -> Self::SnapshotBuilder {
+        RecoverySnapshotBuilder {
+            state_machine: self.state_machine.clone(),
+        }
+    }
+
+    async fn begin_receiving_snapshot(
+        &mut self,
+    ) -> Result<Box<Cursor<Vec<u8>>>, StorageError<NodeId>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    /// Restores a received snapshot as the new state machine contents
+    /// (the "resume reads the installed snapshot" half of recovery),
+    /// then truncates log entries superseded by the snapshot's epoch
+    /// watermark so they aren't replayed, or retained, twice.
+    async fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta<NodeId, BasicNode>,
+        snapshot: Box<Cursor<Vec<u8>>>,
+    ) -> Result<(), StorageError<NodeId>> {
+        let data = snapshot.into_inner();
+        let mut reader = Cursor::new(&data);
+        let mut restored = HashMap::new();
+        while (reader.position() as usize) < data.len() {
+            let (key, bytes): (StoreKey, StateBytes) = bincode::deserialize_from(&mut reader)
+                .map_err(|err| StorageIOError::read_snapshot(None, &err))?;
+            restored.insert(key, bytes);
+        }
+
+        let epoch = restored.keys().map(|key| key.0).max();
+        {
+            let mut state_machine = self.state_machine.write().await;
+            state_machine.store = restored;
+            state_machine.applied.log_id = meta.last_log_id;
+            state_machine.applied.epoch = state_machine.applied.epoch.max(epoch);
+            state_machine.last_membership = meta.last_membership.clone();
+        }
+
+        if let Some(epoch) = epoch {
+            self.truncate_before(epoch)?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_current_snapshot(
+        &mut self,
+    ) -> Result<Option<Snapshot<TypeConfig>>, StorageError<NodeId>> {
+        let mut builder = self.get_snapshot_builder();
+        Ok(Some(builder.build_snapshot().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openraft::CommittedLeaderId;
+
+    fn log_id(index: u64) -> LogId<NodeId> {
+        LogId::new(CommittedLeaderId::new(1, 0), index)
+    }
+
+    fn store_change(epoch: u64, change: Change<StateBytes>) -> StoreChange {
+        let step_id = super::super::model::state::StepId("test_step".into());
+        let state_key = super::super::model::state::StateKey("test_key".into());
+        KChange(
+            StoreKey(
+                SnapshotEpoch(epoch),
+                super::super::model::state::FlowKey(step_id, state_key),
+            ),
+            change,
+        )
+    }
+
+    fn new_store() -> RaftLogStore {
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "bytewax-raft-store-test-{}-{id}",
+            std::process::id()
+        ));
+        RaftLogStore::new(Arc::new(RwLock::new(RecoveryStateMachine::default())), dir)
+    }
+
+    fn snapshot_bytes(entries: &[(StoreKey, StateBytes)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (key, bytes) in entries {
+            bincode::serialize_into(&mut data, &(key, bytes)).unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn install_snapshot_restores_state_and_truncates_superseded_log() {
+        let mut store = new_store();
+
+        // A log entry at epoch 1, older than the snapshot we're about
+        // to install, and one at epoch 3, newer than it.
+        let old_key = StoreKey(
+            SnapshotEpoch(1),
+            super::super::model::state::FlowKey(
+                super::super::model::state::StepId("s".into()),
+                super::super::model::state::StateKey("old".into()),
+            ),
+        );
+        let new_key = StoreKey(
+            SnapshotEpoch(3),
+            super::super::model::state::FlowKey(
+                super::super::model::state::StepId("s".into()),
+                super::super::model::state::StateKey("new".into()),
+            ),
+        );
+        store.log.insert(
+            1,
+            Entry {
+                log_id: log_id(1),
+                payload: EntryPayload::Normal(KChange(
+                    old_key.clone(),
+                    Change::Upsert(StateBytes::ser(&1u64)),
+                )),
+            },
+        );
+        store.log.insert(
+            2,
+            Entry {
+                log_id: log_id(2),
+                payload: EntryPayload::Normal(KChange(
+                    new_key.clone(),
+                    Change::Upsert(StateBytes::ser(&2u64)),
+                )),
+            },
+        );
+
+        let snapshot_key = StoreKey(
+            SnapshotEpoch(2),
+            super::super::model::state::FlowKey(
+                super::super::model::state::StepId("s".into()),
+                super::super::model::state::StateKey("snap".into()),
+            ),
+        );
+        let data = snapshot_bytes(&[(snapshot_key.clone(), StateBytes::ser(&42u64))]);
+        let meta = SnapshotMeta {
+            last_log_id: Some(log_id(1)),
+            last_membership: StoredMembership::default(),
+            snapshot_id: "1-1".into(),
+        };
+
+        store
+            .install_snapshot(&meta, Box::new(Cursor::new(data)))
+            .await
+            .unwrap();
+
+        let state_machine = store.state_machine.read().await;
+        assert!(state_machine.store.contains_key(&snapshot_key));
+        assert_eq!(state_machine.applied.epoch, Some(SnapshotEpoch(2)));
+        drop(state_machine);
+
+        // Epoch 1 is <= the installed snapshot's epoch (2), so it's
+        // superseded and should have been truncated from the log.
+        assert!(!store.log.contains_key(&1));
+        // Epoch 3 is newer than the snapshot, so it must survive.
+        assert!(store.log.contains_key(&2));
+    }
+
+    #[test]
+    fn truncate_before_drops_only_entries_at_or_before_the_given_epoch() {
+        let mut store = new_store();
+        for (index, epoch) in [(1, 1), (2, 2), (3, 3)] {
+            store.log.insert(
+                index,
+                Entry {
+                    log_id: log_id(index),
+                    payload: EntryPayload::Normal(store_change(epoch, Change::Discard)),
+                },
+            );
+        }
+
+        store.truncate_before(SnapshotEpoch(2)).unwrap();
+
+        assert!(!store.log.contains_key(&1));
+        assert!(!store.log.contains_key(&2));
+        assert!(store.log.contains_key(&3));
+    }
+}
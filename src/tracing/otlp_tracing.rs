@@ -0,0 +1,91 @@
+use std::env;
+
+use opentelemetry::{
+    runtime::Tokio,
+    sdk::trace::{config, Sampler, Tracer},
+};
+use opentelemetry_otlp::WithExportConfig;
+use pyo3::prelude::*;
+
+use crate::add_pymethods;
+
+use super::{TracerBuilder, TracingConfig};
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:4317";
+
+/// Configure tracing to send traces to an OTLP-compatible collector.
+///
+/// The endpoint can be configured with the parameter passed to this config,
+/// or with two environment variables:
+///
+///   OTEL_EXPORTER_OTLP_TRACES_ENDPOINT="http://localhost:4317"
+///   OTEL_EXPORTER_OTLP_ENDPOINT="http://localhost:4317"
+///
+/// By default the endpoint is set to "http://localhost:4317".
+///
+/// If the environment variables are set, the endpoint is changed to that.
+///
+/// If a config option is passed to OtlpTracingConfig,
+/// it takes precedence over env vars.
+#[pyclass(module="bytewax.tracing", extends=TracingConfig)]
+#[pyo3(text_signature = "(service_name, endpoint = None, sampling_ratio = 1.0)")]
+#[derive(Clone)]
+pub(crate) struct OtlpTracingConfig {
+    /// Service name, identifies this dataflow.
+    service_name: String,
+    /// Optional OTLP collector's URL
+    endpoint: Option<String>,
+    /// Sampling ratio:
+    ///   samplig_ratio >= 1 - all traces are sampled
+    ///   samplig_ratio <= 0 - no traces are sampled
+    #[pyo3(get)]
+    pub(crate) sampling_ratio: f64,
+}
+
+impl OtlpTracingConfig {
+    fn resolve_endpoint(&self) -> String {
+        self.endpoint
+            .clone()
+            .or_else(|| env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").ok())
+            .or_else(|| env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+            .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+    }
+}
+
+impl TracerBuilder for OtlpTracingConfig {
+    fn build(&self) -> PyResult<Tracer> {
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+        );
+
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(self.resolve_endpoint()),
+            )
+            .with_trace_config(
+                config()
+                    .with_sampler(Sampler::TraceIdRatioBased(self.sampling_ratio))
+                    .with_resource(opentelemetry::sdk::Resource::new(vec![
+                        opentelemetry::KeyValue::new("service.name", self.service_name.clone()),
+                    ])),
+            )
+            .install_batch(Tokio)
+            .unwrap();
+
+        Ok(tracer)
+    }
+}
+
+add_pymethods!(
+    OtlpTracingConfig,
+    parent: TracingConfig,
+    signature: (service_name, endpoint=None, sampling_ratio=1.0),
+    args {
+        service_name: String => String::new(),
+        endpoint: Option<String> => None,
+        sampling_ratio: f64 => 1.0
+    }
+);